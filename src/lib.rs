@@ -17,14 +17,29 @@ extern crate coroutine;
 extern crate nix;
 
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
-use mio::{TryRead, TryWrite, Evented, Token, Handler, EventLoop};
+use mio::{TryRead, TryWrite, TryAccept, Evented, Token, Handler, EventLoop};
+use mio::util::Slab;
+
+/// Message carried over a `Sender`'s `mio::Sender<Msg>`
+///
+/// Carries the id of the coroutine to wake, assigned by `Mioco::register_coroutine` when
+/// the `Sender` was created.
+pub struct Msg(usize);
 
 /// State of `mioco` coroutine
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum State {
     BlockedOnWrite(Token),
     BlockedOnRead(Token),
+    BlockedOnTimer(mio::Timeout),
+    /// Blocked on whichever of these `(Token, Interest)` pairs becomes ready first, as set
+    /// up by `InternalHandle::select`
+    BlockedOnAny(Vec<(Token, mio::Interest)>),
+    /// Parked until woken from another thread via a `Sender`, as set up by
+    /// `InternalHandle::wait_notify`
+    BlockedOnNotify,
     Running,
     Finished,
 }
@@ -45,9 +60,30 @@ impl State {
             } else {
                 mio::Interest::none()
             },
+            // a coroutine blocked purely on a timer is not waiting on this `token`'s IO at all
+            State::BlockedOnTimer(_) => mio::Interest::none(),
+            State::BlockedOnAny(ref waits) => match waits.iter().find(|&&(t, _)| t == token) {
+                Some(&(_, interest)) => interest,
+                None => mio::Interest::none(),
+            },
+            // not waiting on any IO at all while parked on a cross-thread notify
+            State::BlockedOnNotify => mio::Interest::none(),
             State::Finished => mio::Interest::hup(),
         }
     }
+
+    /// Every `Token` this state is currently blocked on, if any
+    ///
+    /// Used to find and reregister whatever IO a coroutine left itself blocked on after a
+    /// wakeup that didn't go through that IO's own `readable`/`writable`/`timeout` (e.g.
+    /// `Handler::notify`).
+    fn blocked_tokens(&self) -> Vec<Token> {
+        match *self {
+            State::BlockedOnRead(token) | State::BlockedOnWrite(token) => vec![token],
+            State::BlockedOnAny(ref waits) => waits.iter().map(|&(t, _)| t).collect(),
+            State::BlockedOnTimer(_) | State::BlockedOnNotify | State::Running | State::Finished => Vec::new(),
+        }
+    }
 }
 
 /// `mioco` can work on any type implementing this trait
@@ -55,6 +91,25 @@ pub trait ReadWrite : TryRead+TryWrite+std::io::Read+std::io::Write+Evented { }
 
 impl<T> ReadWrite for T where T: TryRead+TryWrite+std::io::Read+std::io::Write+Evented {}
 
+/// `mioco` can accept connections off any type implementing this trait
+pub trait Listener : TryAccept+Evented { }
+
+impl<T> Listener for T where T: TryAccept+Evented {}
+
+/// Anything `Mioco` can route a readiness event to by `Token`
+///
+/// Implemented by both `ExternalHandle` (stream IO) and `ExternalAcceptorHandle` (listener
+/// IO) so `Mioco`'s handle slab can hold either behind one `Box<Registered>`.
+trait Registered {
+    fn readable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token, hint: mio::ReadHint);
+    fn writable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token);
+    fn timeout(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token);
+    fn is_finished(&self) -> bool;
+    /// Reregister this handle's `Token`, for a caller that woke it indirectly (`select()`,
+    /// `Handler::notify`) rather than via its own `readable`/`writable`/`timeout`
+    fn reregister(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token);
+}
+
 /// `mioco` coroutine
 ///
 /// Referenced by IO running within it.
@@ -64,6 +119,23 @@ struct Coroutine {
     /// back
     pub state : State,
     coroutine : Option<coroutine::coroutine::Handle>,
+    /// The `EventLoop` currently dispatching into this coroutine, if any.
+    ///
+    /// Set right before every `resume()` of the coroutine (by `Builder::start`,
+    /// `ExternalHandle`, `ExternalAcceptorHandle` and `Mioco::notify`) so that code
+    /// running *inside* the coroutine (e.g. `InternalHandle::sleep_ms`) can reach back out
+    /// to register a `mio::Timeout` on it. Valid only for the duration of that `resume()`
+    /// call, which is the only place the coroutine ever runs.
+    event_loop : *mut EventLoop<Mioco>,
+    /// The `Mioco` currently dispatching into this coroutine, if any.
+    ///
+    /// Set alongside `event_loop`, for the same duration, so that `Spawner::spawn` can queue
+    /// a freshly accepted connection for registration into the same dispatcher that is
+    /// driving the current coroutine.
+    mioco : *mut Mioco,
+    /// Which `Token` woke a `State::BlockedOnAny` wait, consumed by `InternalHandle::select`
+    /// inside the coroutine, right after its `block()` returns.
+    ready_token : Option<Token>,
 }
 
 /// Wrapped mio IO (Evented+TryRead+TryWrite)
@@ -75,13 +147,21 @@ struct IO {
     io : Box<ReadWrite+'static>,
     interest: mio::Interest,
     peer_hup: bool,
+    /// Timeout armed alongside a `BlockedOnRead`/`BlockedOnWrite` wait by a timed `read`/
+    /// `write` (see `InternalHandle::set_timeout_ms`), kept around so it can be cancelled if
+    /// the IO becomes ready first.
+    timeout: Option<mio::Timeout>,
+    /// Milliseconds a `read`/`write` should wait for IO before giving up, if set.
+    timeout_ms: Option<u64>,
+    /// Set by the `timeout()` handler when a timed `read`/`write`'s deadline passes before
+    /// the IO became ready; consumed (and cleared) by that `read`/`write` on its next wakeup.
+    timed_out: bool,
 }
 
 
 impl IO {
     /// Handle `hup` condition
-    fn hup<H>(&mut self, event_loop: &mut EventLoop<H>, token: Token)
-        where H : Handler {
+    fn hup(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token) {
             if self.interest == mio::Interest::hup() {
                 self.interest = mio::Interest::none();
                 event_loop.deregister(&*self.io).ok().expect("deregister() failed");
@@ -92,9 +172,7 @@ impl IO {
         }
 
     /// Reregister oneshot handler for the next event
-    fn reregister<H>(&mut self, event_loop: &mut EventLoop<H>, token : Token)
-        where H : Handler {
-
+    fn reregister(&mut self, event_loop: &mut EventLoop<Mioco>, token : Token) {
             self.interest = self.coroutine.borrow().state.to_interest_for(token) ;
 
             event_loop.reregister(
@@ -102,6 +180,13 @@ impl IO {
                 self.interest, mio::PollOpt::edge() | mio::PollOpt::oneshot()
                 ).ok().expect("reregister failed")
         }
+
+    /// Cancel a timeout armed by a timed `read`/`write`, if IO became ready before it fired
+    fn cancel_timeout(&mut self, event_loop: &mut EventLoop<Mioco>) {
+        if let Some(timeout) = self.timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+    }
 }
 
 /// `mioco` wrapper over io associated with a given coroutine.
@@ -125,6 +210,27 @@ pub struct ExternalHandle {
 pub struct InternalHandle {
     inn : Rc<RefCell<IO>>,
 }
+
+/// Reregister every handle in a `select()` wait set other than `fired_token`, which already
+/// woke and was reregistered by its own caller
+fn reregister_other_waits(mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, waits: &[(Token, mio::Interest)], fired_token: Token) {
+    if mioco.is_null() {
+        return;
+    }
+
+    let mioco : &mut Mioco = unsafe { &mut *mioco };
+
+    for &(t, _) in waits.iter() {
+        if t == fired_token {
+            continue;
+        }
+
+        if let Some(handle) = mioco.handles.get_mut(t) {
+            handle.reregister(event_loop, t);
+        }
+    }
+}
+
 impl ExternalHandle {
 
     /// Is this IO finished and free to be removed
@@ -149,11 +255,34 @@ impl ExternalHandle {
         f(&mut **io)
     }
 
+    /// Wake the coroutine and run it until it blocks or finishes again
+    ///
+    /// Shared by `readable`/`writable`/`timeout`: flips the state to `Running`, records
+    /// `ready_token` for a `select()` to pick up if this wakeup is for a `BlockedOnAny`
+    /// member, exposes `mioco`/`event_loop` to the coroutine for the duration of the
+    /// `resume()` call (so e.g. `Spawner::spawn` can reach them), then clears them
+    /// again.
+    fn wake(&self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, ready_token: Option<Token>) {
+        let handle = {
+            let inn = self.inn.borrow();
+            let coroutine_handle = inn.coroutine.borrow().coroutine.as_ref().map(|c| c.clone()).unwrap();
+            let mut co = inn.coroutine.borrow_mut();
+            co.ready_token = ready_token;
+            co.state = State::Running;
+            co.event_loop = event_loop as *mut _;
+            co.mioco = mioco;
+            coroutine_handle
+        };
+        handle.resume().ok().expect("resume() failed");
+        let mut co = self.inn.borrow().coroutine.borrow_mut();
+        co.event_loop = std::ptr::null_mut();
+        co.mioco = std::ptr::null_mut();
+    }
+
     /// Readable event handler
     ///
     /// This corresponds to `mio::Hnalder::readable()`.
-    pub fn readable<H>(&mut self, event_loop: &mut EventLoop<H>, token: Token, hint: mio::ReadHint)
-    where H : Handler {
+    pub fn readable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token, hint: mio::ReadHint) {
 
         if hint.is_hup() {
             let mut inn = self.inn.borrow_mut();
@@ -165,18 +294,20 @@ impl ExternalHandle {
         let state = {
             let co = &self.inn.borrow().coroutine;
             let co_b = co.borrow();
-            co_b.state
+            co_b.state.clone()
+        };
+
+        let matched_any = match state {
+            State::BlockedOnAny(ref waits) =>
+                waits.iter().any(|&(t, interest)| t == token && interest.contains(mio::Interest::readable())),
+            _ => false,
         };
 
         if let State::BlockedOnRead(blocked_token) = state {
             if token == blocked_token {
-                let handle = {
-                    let inn = self.inn.borrow();
-                    let coroutine_handle = inn.coroutine.borrow().coroutine.as_ref().map(|c| c.clone()).unwrap();
-                    inn.coroutine.borrow_mut().state = State::Running;
-                    coroutine_handle
-                };
-                handle.resume().ok().expect("resume() failed");
+                // IO won the race against any timed read's deadline: the timer is now stale
+                self.inn.borrow_mut().cancel_timeout(event_loop);
+                self.wake(mioco, event_loop, None);
             }
 
             let mut inn = self.inn.borrow_mut();
@@ -186,6 +317,20 @@ impl ExternalHandle {
                 let mut inn = self.inn.borrow_mut();
                 inn.reregister(event_loop, token)
             }
+        } else if matched_any {
+            let waits = match state {
+                State::BlockedOnAny(ref waits) => waits.clone(),
+                _ => unreachable!(),
+            };
+
+            self.wake(mioco, event_loop, Some(token));
+
+            {
+                let mut inn = self.inn.borrow_mut();
+                inn.reregister(event_loop, token)
+            }
+
+            reregister_other_waits(mioco, event_loop, &waits, token);
         }
 
     }
@@ -193,24 +338,25 @@ impl ExternalHandle {
     /// Readable event handler
     ///
     /// This corresponds to `mio::Hnalder::writable()`.
-    pub fn writable<H>(&mut self, event_loop: &mut EventLoop<H>, token: Token)
-    where H : Handler {
+    pub fn writable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token) {
 
         let state = {
             let co = &self.inn.borrow().coroutine;
             let co_b = co.borrow();
-            co_b.state
+            co_b.state.clone()
+        };
+
+        let matched_any = match state {
+            State::BlockedOnAny(ref waits) =>
+                waits.iter().any(|&(t, interest)| t == token && interest.contains(mio::Interest::writable())),
+            _ => false,
         };
 
         if let State::BlockedOnWrite(blocked_token) = state {
             if token == blocked_token {
-                let handle = {
-                    let inn = self.inn.borrow();
-                    let coroutine_handle = inn.coroutine.borrow().coroutine.as_ref().map(|c| c.clone()).unwrap();
-                    inn.coroutine.borrow_mut().state = State::Running;
-                    coroutine_handle
-                };
-                handle.resume().ok().expect("resume() failed");
+                // IO won the race against any timed write's deadline: the timer is now stale
+                self.inn.borrow_mut().cancel_timeout(event_loop);
+                self.wake(mioco, event_loop, None);
 
                 let mut inn = self.inn.borrow_mut();
                 inn.reregister(event_loop, token)
@@ -221,8 +367,166 @@ impl ExternalHandle {
                 let mut inn = self.inn.borrow_mut();
                 inn.reregister(event_loop, token)
             }
+        } else if matched_any {
+            let waits = match state {
+                State::BlockedOnAny(ref waits) => waits.clone(),
+                _ => unreachable!(),
+            };
+
+            self.wake(mioco, event_loop, Some(token));
+
+            {
+                let mut inn = self.inn.borrow_mut();
+                inn.reregister(event_loop, token)
+            }
+
+            reregister_other_waits(mioco, event_loop, &waits, token);
+        }
+    }
+
+    /// Timeout event handler
+    ///
+    /// This corresponds to `mio::Handler::timeout()`. Fired either for a plain
+    /// `InternalHandle::sleep_ms` (`State::BlockedOnTimer`), in which case the coroutine is
+    /// simply woken back up, or for the deadline of a timed `read`/`write`
+    /// (`State::BlockedOnRead`/`BlockedOnWrite` with a `timeout` still armed on the `IO`), in
+    /// which case `timed_out` is set so the blocked `read`/`write` reports it.
+    pub fn timeout(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        let should_wake = {
+            let mut inn = self.inn.borrow_mut();
+            let state = inn.coroutine.borrow().state.clone();
+            match state {
+                State::BlockedOnTimer(_) => true,
+                State::BlockedOnRead(_) | State::BlockedOnWrite(_) if inn.timeout.is_some() => {
+                    inn.timeout = None;
+                    inn.timed_out = true;
+                    true
+                }
+                _ => false,
+            }
+        };
+
+        if !should_wake {
+            return;
+        }
+
+        self.wake(mioco, event_loop, None);
+
+        let mut inn = self.inn.borrow_mut();
+        inn.reregister(event_loop, token)
+    }
+}
+
+impl Registered for ExternalHandle {
+    fn readable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token, hint: mio::ReadHint) {
+        ExternalHandle::readable(self, mioco, event_loop, token, hint)
+    }
+
+    fn writable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        ExternalHandle::writable(self, mioco, event_loop, token)
+    }
+
+    fn timeout(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        ExternalHandle::timeout(self, mioco, event_loop, token)
+    }
+
+    fn is_finished(&self) -> bool {
+        ExternalHandle::is_finished(self)
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        self.inn.borrow_mut().reregister(event_loop, token)
+    }
+}
+
+impl InternalHandle {
+    /// Arm a `mio::Timeout` for `ms` milliseconds on this handle's `token`, stashing it on
+    /// the `IO` so it can be cancelled if IO becomes ready first. Requires the `EventLoop`
+    /// currently dispatching into this coroutine, reached through `Coroutine::event_loop`.
+    fn arm_timeout(&self, ms : u64) -> mio::Timeout {
+        let (event_loop, token) = {
+            let inn = self.inn.borrow();
+            (inn.coroutine.borrow().event_loop, inn.token)
+        };
+
+        debug_assert!(!event_loop.is_null(),
+                       "mioco: blocking call made outside of an event loop dispatch");
+        let event_loop : &mut EventLoop<Mioco> = unsafe { &mut *event_loop };
+
+        event_loop.timeout_ms(token, ms).ok().expect("timeout_ms() failed")
+    }
+
+    /// Set (or clear) a timeout for subsequent `read`/`write` calls
+    ///
+    /// When set, `read`/`write` return a `std::io::ErrorKind::TimedOut` error if the IO does
+    /// not become ready within `ms` milliseconds, mirroring
+    /// `std::net::TcpStream::set_read_timeout`. Pass `None` to wait indefinitely (the
+    /// default).
+    pub fn set_timeout_ms(&mut self, ms : Option<u64>) {
+        self.inn.borrow_mut().timeout_ms = ms;
+    }
+
+    /// Block the current coroutine for `ms` milliseconds
+    pub fn sleep_ms(&mut self, ms : u64) {
+        let timeout = self.arm_timeout(ms);
+
+        {
+            let inn = self.inn.borrow();
+            inn.coroutine.borrow_mut().state = State::BlockedOnTimer(timeout);
+        }
+        coroutine::Coroutine::block();
+    }
+
+    /// Block until any one of `handles` becomes ready per its paired `mio::Interest`,
+    /// returning its index
+    ///
+    /// Lets a coroutine juggling several connections wait for "whichever becomes ready
+    /// first" instead of being limited to one `Token` at a time, on a per-handle mix of
+    /// readability and/or writability. All `handles` must belong to the same coroutine
+    /// (e.g. all came from the same `Builder`).
+    pub fn select(handles : &mut [(InternalHandle, mio::Interest)]) -> usize {
+        assert!(!handles.is_empty(), "mioco: select() called with no handles");
+
+        let waits : Vec<(Token, mio::Interest)> = handles.iter()
+            .map(|&(ref h, interest)| (h.inn.borrow().token, interest))
+            .collect();
+
+        let coroutine = handles[0].0.inn.borrow().coroutine.clone();
+
+        let event_loop = coroutine.borrow().event_loop;
+        debug_assert!(!event_loop.is_null(),
+                       "mioco: select() called outside of an event loop dispatch");
+        let event_loop : &mut EventLoop<Mioco> = unsafe { &mut *event_loop };
+
+        coroutine.borrow_mut().state = State::BlockedOnAny(waits);
+
+        for &(ref h, _) in handles.iter() {
+            let token = h.inn.borrow().token;
+            h.inn.borrow_mut().reregister(event_loop, token);
         }
+
+        coroutine::Coroutine::block();
+
+        let ready_token = coroutine.borrow_mut().ready_token.take()
+            .expect("mioco: select() woke up without a ready token");
+
+        handles.iter().position(|&(ref h, _)| h.inn.borrow().token == ready_token)
+            .expect("mioco: select() woke up on a token not in `handles`")
+    }
+
+    /// Park the current coroutine until it is woken by a `Sender::notify()` call from
+    /// another thread
+    ///
+    /// Use `Builder::sender` (before `start`) to obtain the `Sender` to hand off to whatever
+    /// thread will eventually call `notify()`.
+    pub fn wait_notify(&mut self) {
+        {
+            let inn = self.inn.borrow();
+            inn.coroutine.borrow_mut().state = State::BlockedOnNotify;
+        }
+        coroutine::Coroutine::block();
     }
+
 }
 
 impl std::io::Read for InternalHandle {
@@ -231,11 +535,22 @@ impl std::io::Read for InternalHandle {
             let res = self.inn.borrow_mut().io.try_read(buf);
             match res {
                 Ok(None) => {
+                    let timeout_ms = self.inn.borrow().timeout_ms;
+                    if let Some(ms) = timeout_ms {
+                        let timeout = self.arm_timeout(ms);
+                        self.inn.borrow_mut().timeout = Some(timeout);
+                    }
+
                     {
                         let inn = self.inn.borrow();
                         inn.coroutine.borrow_mut().state = State::BlockedOnRead(inn.token);
                     }
                     coroutine::Coroutine::block();
+
+                    if self.inn.borrow().timed_out {
+                        self.inn.borrow_mut().timed_out = false;
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "read timed out"));
+                    }
                 },
                 Ok(Some(r))  => {
                     return Ok(r);
@@ -254,11 +569,22 @@ impl std::io::Write for InternalHandle {
             let res = self.inn.borrow_mut().io.try_write(buf) ;
             match res {
                 Ok(None) => {
+                    let timeout_ms = self.inn.borrow().timeout_ms;
+                    if let Some(ms) = timeout_ms {
+                        let timeout = self.arm_timeout(ms);
+                        self.inn.borrow_mut().timeout = Some(timeout);
+                    }
+
                     {
                         let inn = self.inn.borrow();
                         inn.coroutine.borrow_mut().state = State::BlockedOnWrite(inn.token);
                     }
                     coroutine::Coroutine::block();
+
+                    if self.inn.borrow().timed_out {
+                        self.inn.borrow_mut().timed_out = false;
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "write timed out"));
+                    }
                 },
                 Ok(Some(r)) => {
                     return Ok(r);
@@ -276,6 +602,383 @@ impl std::io::Write for InternalHandle {
     }
 }
 
+/// Wrapped mio listener (`TryAccept`+`Evented`), the listener counterpart of `IO`
+struct AcceptorIO<T : Listener> {
+    coroutine: Rc<RefCell<Coroutine>>,
+    token: Token,
+    listener: T,
+    interest: mio::Interest,
+}
+
+impl<T : Listener> AcceptorIO<T> {
+    /// Reregister oneshot handler for the next event
+    fn reregister(&mut self, event_loop: &mut EventLoop<Mioco>, token : Token) {
+        self.interest = self.coroutine.borrow().state.to_interest_for(token);
+
+        event_loop.reregister(
+            &self.listener, token,
+            self.interest, mio::PollOpt::edge() | mio::PollOpt::oneshot()
+            ).ok().expect("reregister failed")
+    }
+}
+
+/// `mioco` wrapper over a listener, the `ExternalHandle` counterpart for `Acceptor`
+///
+/// Registered into `Mioco`'s handle slab by `Builder::wrap_listener`; wakes the coroutine
+/// blocked in `Acceptor::accept` when a connection is pending.
+struct ExternalAcceptorHandle<T : Listener> {
+    inn : Rc<RefCell<AcceptorIO<T>>>,
+}
+
+impl<T : Listener> Registered for ExternalAcceptorHandle<T> {
+    fn readable(&mut self, mioco: *mut Mioco, event_loop: &mut EventLoop<Mioco>, token: Token, _hint: mio::ReadHint) {
+        let state = {
+            let co = &self.inn.borrow().coroutine;
+            let co_b = co.borrow();
+            co_b.state.clone()
+        };
+
+        if let State::BlockedOnRead(blocked_token) = state {
+            if token == blocked_token {
+                let handle = {
+                    let inn = self.inn.borrow();
+                    let coroutine_handle = inn.coroutine.borrow().coroutine.as_ref().map(|c| c.clone()).unwrap();
+                    inn.coroutine.borrow_mut().state = State::Running;
+                    inn.coroutine.borrow_mut().event_loop = event_loop as *mut _;
+                    inn.coroutine.borrow_mut().mioco = mioco;
+                    coroutine_handle
+                };
+                handle.resume().ok().expect("resume() failed");
+                let mut co = self.inn.borrow().coroutine.borrow_mut();
+                co.event_loop = std::ptr::null_mut();
+                co.mioco = std::ptr::null_mut();
+            }
+
+            let mut inn = self.inn.borrow_mut();
+            inn.reregister(event_loop, token)
+        }
+    }
+
+    /// Listeners are never blocked on writability
+    fn writable(&mut self, _mioco: *mut Mioco, _event_loop: &mut EventLoop<Mioco>, _token: Token) { }
+
+    /// Listeners don't support timed `accept()` (yet)
+    fn timeout(&mut self, _mioco: *mut Mioco, _event_loop: &mut EventLoop<Mioco>, _token: Token) { }
+
+    fn is_finished(&self) -> bool {
+        let co = &self.inn.borrow().coroutine;
+        let co_b = co.borrow();
+        co_b.state == State::Finished && self.inn.borrow().interest == mio::Interest::none()
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        self.inn.borrow_mut().reregister(event_loop, token)
+    }
+}
+
+/// `mioco` wrapper over a listener, passed to the coroutine closure
+///
+/// Mirrors `InternalHandle`, but for accepting connections instead of reading/writing an
+/// established one. Create with `Builder::wrap_listener`.
+pub struct Acceptor<T : Listener> {
+    inn : Rc<RefCell<AcceptorIO<T>>>,
+}
+
+unsafe impl<T : Listener> Send for Acceptor<T> { }
+
+impl<T : Listener> Acceptor<T> {
+    /// Accept the next connection, blocking the coroutine until one arrives
+    pub fn accept(&mut self) -> std::io::Result<T::Output> {
+        loop {
+            let res = self.inn.borrow().listener.accept();
+            match res {
+                Ok(None) => {
+                    {
+                        let inn = self.inn.borrow();
+                        inn.coroutine.borrow_mut().state = State::BlockedOnRead(inn.token);
+                    }
+                    coroutine::Coroutine::block();
+                },
+                Ok(Some(conn)) => {
+                    return Ok(conn);
+                },
+                Err(e) => {
+                    return Err(e)
+                }
+            }
+        }
+    }
+}
+
+/// Handle letting another thread wake a coroutine parked via `InternalHandle::wait_notify`
+///
+/// Wraps a `mio::Sender<Msg>` obtained from `EventLoop::channel`, plus the id `Mioco`
+/// assigned the target coroutine. Create one with `Builder::sender`, then move it onto
+/// whatever thread (channel consumer, thread pool, DNS resolver, ...) produces the result
+/// the coroutine is waiting for; `notify()` from there wakes it back up.
+pub struct Sender {
+    id : usize,
+    tx : mio::Sender<Msg>,
+}
+
+unsafe impl Send for Sender {}
+
+impl Sender {
+    /// Wake the coroutine this `Sender` was created for
+    pub fn notify(&self) -> Result<(), mio::NotifyError<Msg>> {
+        self.tx.send(Msg(self.id))
+    }
+}
+
+/// A `Spawner::spawn` call queued for `Mioco::drain_pending` to register later, once it's
+/// safe to touch `Mioco::handles` again
+trait PendingSpawn {
+    fn run(&mut self, mioco: &mut Mioco, event_loop: &mut EventLoop<Mioco>);
+}
+
+struct PendingSpawnImpl<T, F> {
+    io: Option<T>,
+    f: Option<F>,
+}
+
+impl<T, F> PendingSpawn for PendingSpawnImpl<T, F>
+    where T : ReadWrite + 'static, F : FnOnce(&mut [InternalHandle]) + Send + 'static {
+    fn run(&mut self, mioco: &mut Mioco, event_loop: &mut EventLoop<Mioco>) {
+        let io = self.io.take().expect("PendingSpawnImpl::run called twice");
+        let f = self.f.take().expect("PendingSpawnImpl::run called twice");
+
+        let mut builder = Builder::new();
+        builder.wrap_io(mioco, event_loop, io);
+        builder.start(mioco, event_loop, f);
+    }
+}
+
+/// Lets a coroutine spawn a new, independent child coroutine to handle a freshly accepted
+/// `mio` source
+///
+/// Obtained via `Builder::spawner`, independently of whatever handles the coroutine itself
+/// was built with -- in particular this is how a `Builder::wrap_listener`-only accept loop
+/// hands each accepted connection off to its own coroutine.
+pub struct Spawner {
+    coroutine : Rc<RefCell<Coroutine>>,
+}
+
+unsafe impl Send for Spawner {}
+
+impl Spawner {
+    /// Register `io` and start a brand new coroutine running `f`, as `Builder::start` would
+    /// for one set up ahead of time
+    ///
+    /// Queued rather than registered right away: doing it synchronously could insert into
+    /// `Mioco::handles` while the caller's own dispatch is still holding a `&mut` into it.
+    pub fn spawn<T, F>(&self, io : T, f : F)
+        where T : ReadWrite + 'static, F : FnOnce(&mut [InternalHandle]) + Send + 'static {
+
+        let mioco = self.coroutine.borrow().mioco;
+
+        debug_assert!(!mioco.is_null(),
+                       "mioco: spawn() called outside of an event loop dispatch");
+        let mioco : &mut Mioco = unsafe { &mut *mioco };
+
+        mioco.queue_spawn(io, f);
+    }
+}
+
+/// `mioco` dispatcher
+///
+/// Implements `mio::Handler`: `readable`/`writable`/`timeout` look the handle up by `token`
+/// and forward the event to it, keyed by the `mio::Token` it was registered with.
+/// Holds `ExternalHandle`s (stream IO, from `Builder::wrap_io`) and `ExternalAcceptorHandle`s
+/// (listener IO, from `Builder::wrap_listener`) side by side behind `Box<Registered>`.
+pub struct Mioco {
+    handles : Slab<Box<Registered>>,
+    coroutines : HashMap<usize, Rc<RefCell<Coroutine>>>,
+    next_coroutine_id : usize,
+    /// `Spawner::spawn` calls queued while a `Registered` dispatch was in progress, drained
+    /// by `drain_pending` once it's safe to touch `handles` again
+    pending : Vec<Box<PendingSpawn>>,
+}
+
+impl Mioco {
+    /// Create a new, empty `Mioco` dispatcher with room for 1024 handles
+    ///
+    /// `insert_with` panics once that many are registered at once; use `with_capacity` to
+    /// size the slab for a long-running server instead.
+    pub fn new() -> Mioco {
+        Mioco::with_capacity(1024)
+    }
+
+    /// Create a new, empty `Mioco` dispatcher with room for `capacity` handles
+    pub fn with_capacity(capacity : usize) -> Mioco {
+        Mioco {
+            handles: Slab::new(capacity),
+            coroutines: HashMap::new(),
+            next_coroutine_id: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Register an already-wrapped handle, returning the `Token` it now owns
+    ///
+    /// Used by `Builder::wrap_io`/`Builder::wrap_listener`; not normally called directly.
+    fn insert_with<F>(&mut self, f : F) -> Token
+        where F : FnOnce(Token) -> Box<Registered> {
+        self.handles.insert_with(f).expect("Mioco: handle slab is full")
+    }
+
+    /// Queue a `Spawner::spawn` call for registration once the current `Registered` dispatch
+    /// (if any) has released its borrow of `handles`
+    fn queue_spawn<T, F>(&mut self, io : T, f : F)
+        where T : ReadWrite + 'static, F : FnOnce(&mut [InternalHandle]) + Send + 'static {
+        self.pending.push(Box::new(PendingSpawnImpl { io: Some(io), f: Some(f) }));
+    }
+
+    /// Register every `Spawner::spawn` call queued since the last drain
+    ///
+    /// Called at the end of each `Handler` callback, by which point nothing else still holds
+    /// a reference into `handles`.
+    fn drain_pending(&mut self, event_loop: &mut EventLoop<Mioco>) {
+        let mut pending = std::mem::replace(&mut self.pending, Vec::new());
+        for p in pending.iter_mut() {
+            p.run(self, event_loop);
+        }
+    }
+
+    /// Register a coroutine so it can be found by id when a `Sender` fires, returning that id
+    ///
+    /// Used by `Builder::sender`; not normally called directly.
+    fn register_coroutine(&mut self, coroutine : Rc<RefCell<Coroutine>>) -> usize {
+        let id = self.next_coroutine_id;
+        self.next_coroutine_id += 1;
+        self.coroutines.insert(id, coroutine);
+        id
+    }
+
+    /// Drop finished coroutines from `coroutines`
+    ///
+    /// `register_coroutine` entries outlive the coroutine itself (a `Sender` may still be
+    /// held by another thread), so without this a long-running dispatcher handing out a
+    /// `Sender` per spawned connection leaks one map entry per connection forever.
+    fn prune_finished_coroutines(&mut self) {
+        let finished_ids : Vec<usize> = self.coroutines.iter()
+            .filter(|&(_, co)| co.borrow().state == State::Finished)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in finished_ids {
+            self.coroutines.remove(&id);
+        }
+    }
+}
+
+impl Handler for Mioco {
+    type Timeout = Token;
+    type Message = Msg;
+
+    fn readable(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token, hint: mio::ReadHint) {
+        // Captured before borrowing `self.handles` so the `Registered` impl being dispatched
+        // to can reach back into `self` (e.g. `Spawner::spawn` queueing a new handle via
+        // `queue_spawn`) without needing a second `&mut Mioco` on the stack. Only disjoint
+        // fields of `self` (not `handles`) may be touched through it until this borrow ends.
+        let mioco = self as *mut Mioco;
+        let finished = match self.handles.get_mut(token) {
+            Some(handle) => {
+                handle.readable(mioco, event_loop, token, hint);
+                handle.is_finished()
+            }
+            None => return,
+        };
+
+        if finished {
+            self.handles.remove(token);
+        }
+
+        self.drain_pending(event_loop);
+        self.prune_finished_coroutines();
+    }
+
+    fn writable(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        let mioco = self as *mut Mioco;
+        let finished = match self.handles.get_mut(token) {
+            Some(handle) => {
+                handle.writable(mioco, event_loop, token);
+                handle.is_finished()
+            }
+            None => return,
+        };
+
+        if finished {
+            self.handles.remove(token);
+        }
+
+        self.drain_pending(event_loop);
+        self.prune_finished_coroutines();
+    }
+
+    /// `mioco`'s timers reuse the same `Token` space as readiness events: the `Token`
+    /// identifies the `ExternalHandle` the timer belongs to.
+    fn timeout(&mut self, event_loop: &mut EventLoop<Mioco>, token: Token) {
+        let mioco = self as *mut Mioco;
+        let finished = match self.handles.get_mut(token) {
+            Some(handle) => {
+                handle.timeout(mioco, event_loop, token);
+                handle.is_finished()
+            }
+            None => return,
+        };
+
+        if finished {
+            self.handles.remove(token);
+        }
+
+        self.drain_pending(event_loop);
+        self.prune_finished_coroutines();
+    }
+
+    /// Wake a coroutine parked via `InternalHandle::wait_notify`, from another thread's
+    /// `Sender::notify()`
+    fn notify(&mut self, event_loop: &mut EventLoop<Mioco>, msg: Msg) {
+        let Msg(id) = msg;
+
+        let mioco = self as *mut Mioco;
+
+        let coroutine = match self.coroutines.get(&id) {
+            Some(coroutine) => coroutine.clone(),
+            None => return,
+        };
+
+        if coroutine.borrow().state != State::BlockedOnNotify {
+            return;
+        }
+
+        let handle = {
+            let mut co = coroutine.borrow_mut();
+            co.state = State::Running;
+            co.event_loop = event_loop as *mut _;
+            co.mioco = mioco;
+            co.coroutine.as_ref().map(|c| c.clone()).unwrap()
+        };
+        handle.resume().ok().expect("resume() failed");
+        let mut co = coroutine.borrow_mut();
+        co.event_loop = std::ptr::null_mut();
+        co.mioco = std::ptr::null_mut();
+        drop(co);
+
+        // Unlike the readable/writable/timeout paths, nothing else reregisters whatever IO
+        // the coroutine left itself blocked on: without this it hangs forever waiting for an
+        // event that mio will never redeliver.
+        let blocked_tokens = coroutine.borrow().state.blocked_tokens();
+        for token in blocked_tokens {
+            if let Some(handle) = self.handles.get_mut(token) {
+                handle.reregister(event_loop, token);
+            }
+        }
+
+        self.drain_pending(event_loop);
+        self.prune_finished_coroutines();
+    }
+}
+
 /// `mioco` coroutine builder
 ///
 /// Create one with `new`, then use `wrap_io` on io that you are going to use in the coroutine
@@ -302,6 +1005,9 @@ impl Builder {
             coroutine: Rc::new(RefCell::new(Coroutine {
                 state: State::Running,
                 coroutine: None,
+                event_loop: std::ptr::null_mut(),
+                mioco: std::ptr::null_mut(),
+                ready_token: None,
             })),
             handles: Vec::with_capacity(4),
         }
@@ -309,42 +1015,114 @@ impl Builder {
 
     /// Register `mio`'s io to be used within `mioco` coroutine
     ///
-    /// Consumes the `io`, returns a `Handle` to a mio wrapper over it.
-    pub fn wrap_io<H, T : 'static>(&mut self, event_loop: &mut mio::EventLoop<H>, io : T, token : Token) -> ExternalHandle
-    where H : Handler,
-    T : ReadWrite {
-
-        event_loop.register_opt(
-            &io, token,
-            mio::Interest::readable() | mio::Interest::writable(), mio::PollOpt::edge() | mio::PollOpt::oneshot()
-            ).expect("register_opt failed");
-
-        let io = Rc::new(RefCell::new(
-                     IO {
-                         coroutine: self.coroutine.clone(),
-                         io: Box::new(io),
-                         token: token,
-                         peer_hup: false,
-                         interest: mio::Interest::none(),
-                     }
-                 ));
-
-        let handle = ExternalHandle {
-            inn: io.clone()
-        };
+    /// Consumes the `io`, registers it with `mioco`'s shared handle slab and returns the
+    /// `Token` it was assigned. `Mioco` (via its `mio::Handler` impl) owns the resulting
+    /// `ExternalHandle` from here on, so there is no loose handle for the caller to juggle.
+    pub fn wrap_io<T : 'static>(&mut self, mioco : &mut Mioco, event_loop: &mut mio::EventLoop<Mioco>, io : T) -> Token
+    where T : ReadWrite {
+
+        let coroutine = self.coroutine.clone();
+        let mut internal_handle = None;
+
+        let token = mioco.insert_with(|token| {
+            event_loop.register_opt(
+                &io, token,
+                mio::Interest::readable() | mio::Interest::writable(), mio::PollOpt::edge() | mio::PollOpt::oneshot()
+                ).expect("register_opt failed");
+
+            let io = Rc::new(RefCell::new(
+                         IO {
+                             coroutine: coroutine,
+                             io: Box::new(io),
+                             token: token,
+                             peer_hup: false,
+                             interest: mio::Interest::none(),
+                             timeout: None,
+                             timeout_ms: None,
+                             timed_out: false,
+                         }
+                     ));
+
+            internal_handle = Some(InternalHandle { inn: io.clone() });
+
+            Box::new(ExternalHandle { inn: io }) as Box<Registered>
+        });
 
-        self.handles.push(InternalHandle {
-            inn: io.clone()
+        self.handles.push(internal_handle.expect("insert_with did not run"));
+
+        token
+    }
+
+    /// Register a `mio` listener to be `accept()`-ed from within the `mioco` coroutine
+    ///
+    /// Consumes the `listener`, registers it with `mioco`'s shared handle slab like
+    /// `wrap_io` does, and returns an `Acceptor` the coroutine can `accept()` on in a loop.
+    /// Unlike the handles from `wrap_io`, the returned `Acceptor` is not collected into
+    /// `Builder::start`'s `&mut [InternalHandle]` — move it into the closure passed to
+    /// `start` directly.
+    pub fn wrap_listener<T : 'static>(&mut self, mioco : &mut Mioco, event_loop: &mut mio::EventLoop<Mioco>, listener : T) -> Acceptor<T>
+    where T : Listener {
+
+        let coroutine = self.coroutine.clone();
+        let mut acceptor = None;
+
+        mioco.insert_with(|token| {
+            event_loop.register_opt(
+                &listener, token,
+                mio::Interest::readable(), mio::PollOpt::edge() | mio::PollOpt::oneshot()
+                ).expect("register_opt failed");
+
+            let io = Rc::new(RefCell::new(
+                         AcceptorIO {
+                             coroutine: coroutine,
+                             listener: listener,
+                             token: token,
+                             interest: mio::Interest::none(),
+                         }
+                     ));
+
+            acceptor = Some(Acceptor { inn: io.clone() });
+
+            Box::new(ExternalAcceptorHandle { inn: io }) as Box<Registered>
         });
 
-        handle
+        acceptor.expect("insert_with did not run")
+    }
+
+    /// Create a `Sender` that other threads can use to wake this coroutine
+    ///
+    /// Registers the coroutine with `mioco` so `Mioco::notify` can find it again, and wraps
+    /// a `mio::Sender` obtained from `event_loop.channel()`. Hand the returned `Sender` off
+    /// to whatever thread (channel consumer, thread pool, DNS resolver, ...) should be able
+    /// to wake the coroutine once it parks itself with `InternalHandle::wait_notify`.
+    pub fn sender(&mut self, mioco : &mut Mioco, event_loop: &mut mio::EventLoop<Mioco>) -> Sender {
+        let id = mioco.register_coroutine(self.coroutine.clone());
+
+        Sender {
+            id: id,
+            tx: event_loop.channel(),
+        }
+    }
+
+    /// Create a `Spawner` this coroutine can use to hand off freshly accepted connections to
+    /// their own child coroutines
+    ///
+    /// Unlike `sender`, needs no `Mioco`/`EventLoop` up front: a `Spawner` only needs to reach
+    /// the `Mioco`/`EventLoop` currently driving its own coroutine, which it does lazily,
+    /// through `Coroutine::mioco`, at `spawn()` time.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            coroutine: self.coroutine.clone(),
+        }
     }
 
     /// Create a `mioco` coroutine handler
     ///
     /// `f` is routine handling connection. It should not use any blocking operations,
-    /// and use it's argument for all IO with it's peer
-    pub fn start<F>(self, f : F)
+    /// and use it's argument for all IO with it's peer. `mioco`/`event_loop` are exposed to
+    /// `f` for the duration of this first run (same as every later wakeup), so the coroutine
+    /// can e.g. `sleep_ms` or `Spawner::spawn` as its very first action.
+    pub fn start<F>(self, mioco : &mut Mioco, event_loop : &mut EventLoop<Mioco>, f : F)
         where F : FnOnce(&mut [InternalHandle]) + Send + 'static {
 
             let ioref = RefCoroutine {
@@ -360,6 +1138,47 @@ impl Builder {
                 ioref.coroutine.borrow_mut().state = State::Finished;
             });
 
+            self.coroutine.borrow_mut().event_loop = event_loop as *mut _;
+            self.coroutine.borrow_mut().mioco = mioco as *mut _;
+
             coroutine_handle.resume().ok().expect("resume() failed");
+
+            let mut co = self.coroutine.borrow_mut();
+            co.event_loop = std::ptr::null_mut();
+            co.mioco = std::ptr::null_mut();
         }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream as StdTcpStream;
+
+    /// Regression test for a coroutine calling `sleep_ms` as its very first action, before
+    /// ever blocking on IO once -- `Coroutine::event_loop`/`mioco` used to still be null at
+    /// that point, since they were only ever stamped on by a *later* wakeup.
+    #[test]
+    fn sleep_ms_as_first_action_does_not_panic() {
+        let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _peer = StdTcpStream::connect(addr).unwrap();
+
+        let mut accepted = None;
+        for _ in 0..1000 {
+            if let Some(stream) = listener.accept().unwrap() {
+                accepted = Some(stream);
+                break;
+            }
+        }
+        let stream = accepted.expect("accept did not complete");
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut mioco = Mioco::new();
+
+        let mut builder = Builder::new();
+        builder.wrap_io(&mut mioco, &mut event_loop, stream);
+        builder.start(&mut mioco, &mut event_loop, |handles| {
+            handles[0].sleep_ms(1);
+        });
+    }
+}